@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use std::io::SeekFrom;
+
+use tap::vfile::VFile;
+use tap::error;
+
+use crate::{Partition, APM, SECTOR_SIZE};
+
+use byteorder::{ByteOrder, BigEndian};
+
+const DRIVER_DESCRIPTOR_SIGNATURE: u16 = 0x4552; // 'ER'
+const ENTRY_SIGNATURE: u16 = 0x504D; // 'PM'
+const FREE_PARTITION_TYPE: &str = "Apple_Free";
+//hard cap on pmMapBlkCnt, guards against a corrupt/adversarial map claiming an inflated entry count
+const MAX_MAP_ENTRIES: u32 = 4096;
+
+/// Read an Apple Partition Map: block 0 is the Driver Descriptor Record, the map itself starts
+/// at block 1 where each entry is self-describing its own entry count (`pmMapBlkCnt`).
+pub fn apm_partition_table<T: VFile>(file: &mut T) -> anyhow::Result<Vec<Partition>>
+{
+  file.seek(SeekFrom::Start(0))?;
+  let mut block0 = [0u8; SECTOR_SIZE];
+  file.read_exact(&mut block0)?;
+
+  if DRIVER_DESCRIPTOR_SIGNATURE != BigEndian::read_u16(&block0[0x00..0x02])
+  {
+    return Err(error::RustructError::Unknown("bad Driver Descriptor Record signature".into()).into());
+  }
+
+  let mut partitions = Vec::with_capacity(16);
+  let mut map_entries = None;
+  let mut id : u32 = 0;
+
+  loop
+  {
+    if let Some(map_entries) = map_entries
+    {
+      if id >= map_entries
+      {
+        break;
+      }
+    }
+
+    file.seek(SeekFrom::Start((1 + id as u64) * SECTOR_SIZE as u64))?;
+    let mut entry = [0u8; SECTOR_SIZE];
+    file.read_exact(&mut entry)?;
+
+    if ENTRY_SIGNATURE != BigEndian::read_u16(&entry[0x00..0x02])
+    {
+      return Err(error::RustructError::Unknown(format!("partition map entry {} has a bad signature", id)).into());
+    }
+
+    let entry_map_entries = BigEndian::read_u32(&entry[0x04..0x08]);
+    if entry_map_entries > MAX_MAP_ENTRIES
+    {
+      return Err(error::RustructError::Unknown(format!("partition map entry count {} exceeds sane limit", entry_map_entries)).into());
+    }
+
+    let map_entries = *map_entries.get_or_insert(entry_map_entries);
+    if entry_map_entries != map_entries
+    {
+      return Err(error::RustructError::Unknown("inconsistent partition map entry count".into()).into());
+    }
+
+    let start_block = BigEndian::read_u32(&entry[0x08..0x0c]);
+    let block_count = BigEndian::read_u32(&entry[0x0c..0x10]);
+    let name = read_cstr(&entry[0x10..0x30]);
+    let partition_type = read_cstr(&entry[0x30..0x50]);
+
+    if partition_type != FREE_PARTITION_TYPE
+    {
+      partitions.push(Partition
+      {
+        id : id as usize + 1,
+        start_sector : start_block as u64,
+        number_of_sector : block_count as u64,
+        sector_size : SECTOR_SIZE as u64,
+        mbr : None,
+        gpt : None,
+        apm : Some(Arc::new(APM{name, partition_type})),
+      });
+    }
+
+    id += 1;
+  }
+
+  Ok(partitions)
+}
+
+fn read_cstr(bytes: &[u8]) -> String
+{
+  let end = bytes.iter().position(|&b| 0 == b).unwrap_or(bytes.len());
+  String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn read_cstr_stops_at_the_nul_terminator()
+  {
+    let mut bytes = [0u8; 16];
+    bytes[..9].copy_from_slice(b"Apple_HFS");
+    assert_eq!("Apple_HFS", read_cstr(&bytes));
+  }
+
+  #[test]
+  fn read_cstr_uses_the_whole_buffer_if_there_is_no_terminator()
+  {
+    let bytes = [b'x'; 4];
+    assert_eq!("xxxx", read_cstr(&bytes));
+  }
+
+  fn block0() -> [u8; SECTOR_SIZE]
+  {
+    let mut block = [0u8; SECTOR_SIZE];
+    BigEndian::write_u16(&mut block[0x00..0x02], DRIVER_DESCRIPTOR_SIGNATURE);
+    block
+  }
+
+  fn map_entry(entry_map_entries: u32, start_block: u32, block_count: u32, partition_type: &str) -> [u8; SECTOR_SIZE]
+  {
+    let mut entry = [0u8; SECTOR_SIZE];
+    BigEndian::write_u16(&mut entry[0x00..0x02], ENTRY_SIGNATURE);
+    BigEndian::write_u32(&mut entry[0x04..0x08], entry_map_entries);
+    BigEndian::write_u32(&mut entry[0x08..0x0c], start_block);
+    BigEndian::write_u32(&mut entry[0x0c..0x10], block_count);
+    entry[0x30..0x30 + partition_type.len()].copy_from_slice(partition_type.as_bytes());
+    entry
+  }
+
+  #[test]
+  fn apm_partition_table_rejects_an_inflated_map_entry_count()
+  {
+    let mut disk = block0().to_vec();
+    disk.extend_from_slice(&map_entry(MAX_MAP_ENTRIES + 1, 1, 100, "Apple_HFS"));
+
+    let mut file = std::io::Cursor::new(disk);
+    let err = apm_partition_table(&mut file).unwrap_err();
+    assert!(err.to_string().contains("exceeds sane limit"));
+  }
+
+  #[test]
+  fn apm_partition_table_reads_a_single_partition_entry()
+  {
+    let mut disk = block0().to_vec();
+    disk.extend_from_slice(&map_entry(1, 1, 100, "Apple_HFS"));
+
+    let mut file = std::io::Cursor::new(disk);
+    let partitions = apm_partition_table(&mut file).unwrap();
+    assert_eq!(1, partitions.len());
+    assert_eq!(1, partitions[0].start_sector);
+    assert_eq!(100, partitions[0].number_of_sector);
+  }
+}
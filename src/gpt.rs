@@ -9,96 +9,234 @@ use crate::{Partition, GPT};
 use byteorder::{ByteOrder, LittleEndian};
 use crc::crc32::checksum_ieee;
 
-pub fn gpt_from_file<T: VFile>(file: &mut T, sector_size: u64) -> anyhow::Result<Vec<Partition>>
+//well-known GPT partition type GUIDs, uppercase canonical form, mapped to a human-readable name
+const KNOWN_TYPE_GUIDS: &[(&str, &str)] = &[
+  ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System Partition"),
+  ("21686148-6449-6E6F-744E-656564454649", "BIOS Boot Partition"),
+  ("E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "Microsoft Reserved"),
+  ("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "Microsoft Basic Data"),
+  ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "Linux filesystem data"),
+  ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+  ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+  ("A19D880F-05FC-4D3B-A006-743F0F84911E", "Linux RAID"),
+  ("48465300-0000-11AA-AA11-00306543ECAC", "Apple HFS+"),
+];
+
+//format a 16-byte mixed-endian GPT GUID (first three fields little-endian, last two big-endian)
+//into the canonical 8-4-4-4-12 string
+fn format_guid(bytes: &[u8]) -> String
 {
-    file.seek(SeekFrom::Start(sector_size))?;
+  format!("{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+          LittleEndian::read_u32(&bytes[0x0..0x4]),
+          LittleEndian::read_u16(&bytes[0x4..0x6]),
+          LittleEndian::read_u16(&bytes[0x6..0x8]),
+          bytes[0x8], bytes[0x9],
+          bytes[0xa], bytes[0xb], bytes[0xc], bytes[0xd], bytes[0xe], bytes[0xf])
+}
 
-    let mut lba1 = vec![0u8; sector_size as usize];
-    file.read_exact(&mut lba1)?;
+fn type_name_for_guid(type_uuid: &str) -> String
+{
+  KNOWN_TYPE_GUIDS.iter()
+    .find(|(guid, _)| *guid == type_uuid)
+    .map(|(_, name)| name.to_string())
+    .unwrap_or_default()
+}
+
+//hard caps on the partition entry array, guards against a corrupt/adversarial header forcing a huge allocation
+const MAX_ENTRIES: u32 = 4096;
+const MAX_ENTRY_SIZE: u32 = 4096;
+
+//GPT partition entry attribute bits, from the UEFI spec
+const ATTR_REQUIRED_PARTITION: u64 = 1 << 0;
+const ATTR_NO_BLOCK_IO_PROTOCOL: u64 = 1 << 1;
+const ATTR_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+const ATTR_READ_ONLY: u64 = 1 << 60;
+const ATTR_HIDDEN: u64 = 1 << 62;
+const ATTR_NO_AUTOMOUNT: u64 = 1 << 63;
 
-    if b"EFI PART" != &lba1[0x00..0x08] 
+//fields pulled out of a (primary or backup) GPT header that the rest of the parser needs
+struct Header
+{
+  my_lba : u64,
+  alternate_lba : u64,
+  first_usable_lba : u64,
+  last_usable_lba : u64,
+  partition_entry_lba : u64,
+  entries : u32,
+  entry_size : u32,
+  array_crc : u32,
+}
+
+//parse and validate the fields common to both the primary and the backup header; lba-specific checks
+//(which lba this header must sit at, where its entry array must start) are the caller's responsibility
+fn parse_header(lba : &mut [u8], sector_size : u64) -> anyhow::Result<Header>
+{
+    if b"EFI PART" != &lba[0x00..0x08]
     {
       return Err(error::RustructError::Unknown("bad EFI signature".into()).into());
     }
 
-    if [0, 0, 1, 0] != lba1[0x08..0x0c] 
+    if [0, 0, 1, 0] != lba[0x08..0x0c]
     {
       return Err(error::RustructError::Unknown("unsupported revision".into()).into());
     }
-    let header_size = LittleEndian::read_u32(&lba1[0x0c..0x10]);
-    if header_size < 92 
+    let header_size = LittleEndian::read_u32(&lba[0x0c..0x10]);
+    if header_size < 92 || header_size as usize > lba.len()
     {
-      return Err(error::RustructError::Unknown("header too short".into()).into());
+      return Err(error::RustructError::Unknown("header size is out of bounds".into()).into());
     }
 
-    let header_crc = LittleEndian::read_u32(&lba1[0x10..0x14]);
+    let header_crc = LittleEndian::read_u32(&lba[0x10..0x14]);
 
     #[allow(clippy::needless_range_loop)]
-    for crc_part in 0x10..0x14 
+    for crc_part in 0x10..0x14
     {
-        lba1[crc_part] = 0;
+        lba[crc_part] = 0;
     }
 
-    if header_crc != checksum_ieee(&lba1[..header_size as usize]) 
+    if header_crc != checksum_ieee(&lba[..header_size as usize])
     {
         return Err(error::RustructError::Unknown("header checksum mismatch".into()).into());
     }
 
-    if 0 != LittleEndian::read_u32(&lba1[0x14..0x18]) 
+    if 0 != LittleEndian::read_u32(&lba[0x14..0x18])
     {
         return Err(error::RustructError::Unknown("unsupported data in reserved field 0x0c".into()).into());
     }
 
-    if 1 != LittleEndian::read_u64(&lba1[0x18..0x20]) 
+    let my_lba = LittleEndian::read_u64(&lba[0x18..0x20]);
+    let alternate_lba = LittleEndian::read_u64(&lba[0x20..0x28]);
+
+    let first_usable_lba = LittleEndian::read_u64(&lba[0x28..0x30]);
+    let last_usable_lba = LittleEndian::read_u64(&lba[0x30..0x38]);
+
+    if first_usable_lba > last_usable_lba
     {
-        return Err(error::RustructError::Unknown("current lba must be '1' for first header".into()).into());
+        return Err(error::RustructError::Unknown("usable lbas are backwards?!".into()).into());
     }
 
-    let first_usable_lba = LittleEndian::read_u64(&lba1[0x28..0x30]);
-    let last_usable_lba = LittleEndian::read_u64(&lba1[0x30..0x38]);
+    let partition_entry_lba = LittleEndian::read_u64(&lba[0x48..0x50]);
 
-    if first_usable_lba > last_usable_lba 
+    let entries = LittleEndian::read_u32(&lba[0x50..0x54]);
+    let entry_size = LittleEndian::read_u32(&lba[0x54..0x58]);
+    let array_crc = LittleEndian::read_u32(&lba[0x58..0x5c]);
+
+    if entry_size < 128
     {
-        return Err(error::RustructError::Unknown("usable lbas are backwards?!".into()).into());
+        return Err(error::RustructError::Unknown("entry size is implausibly small".into()).into());
     }
 
-    let mut guid = [0u8; 16];
-    guid.copy_from_slice(&lba1[0x38..0x48]);
+    if entry_size > MAX_ENTRY_SIZE || entries > MAX_ENTRIES
+    {
+        return Err(error::RustructError::Unknown(format!("partition entry array of {} x {} bytes exceeds sane limits", entries, entry_size)).into());
+    }
 
-    if 2 != LittleEndian::read_u64(&lba1[0x48..0x50]) 
+    if first_usable_lba < 2 + ((u64::from(entry_size) * u64::from(entries)) / sector_size)
     {
-        return Err(error::RustructError::Unknown("starting lba must be '2' for first header".into()).into());
+        return Err(error::RustructError::Unknown("first usable lba is too low".into()).into());
     }
 
-    let entries = LittleEndian::read_u32(&lba1[0x50..0x54]);
-    let entry_size = LittleEndian::read_u32(&lba1[0x54..0x58]);
+    if !all_zero(&lba[header_size as usize..])
+    {
+        return Err(error::RustructError::Unknown("reserved header tail is not all empty".into()).into());
+    }
+
+    Ok(Header{ my_lba, alternate_lba, first_usable_lba, last_usable_lba, partition_entry_lba, entries, entry_size, array_crc })
+}
+
+//read and validate the primary header at lba 1; it must sit at lba 1 and its entry array must start at lba 2
+fn read_primary_header<T: VFile>(file: &mut T, sector_size: u64) -> anyhow::Result<Header>
+{
+    file.seek(SeekFrom::Start(sector_size))?;
+
+    let mut lba1 = vec![0u8; sector_size as usize];
+    file.read_exact(&mut lba1)?;
+
+    let header = parse_header(&mut lba1, sector_size)?;
 
-    if entry_size < 128 
+    if 1 != header.my_lba
     {
-        return Err(error::RustructError::Unknown("entry size is implausibly small".into()).into());
+        return Err(error::RustructError::Unknown("current lba must be '1' for first header".into()).into());
     }
 
+    if 2 != header.partition_entry_lba
+    {
+        return Err(error::RustructError::Unknown("starting lba must be '2' for first header".into()).into());
+    }
 
-    if first_usable_lba < 2 + ((u64::from(entry_size) * u64::from(entries)) / sector_size) 
+    Ok(header)
+}
+
+//the primary header was unreadable: fall back to the backup header kept at the end of the disk.
+//its own lba is taken from whatever the primary's alternate_lba field says if the bytes we already
+//read for the primary are usable, otherwise it's derived from the file size.
+fn read_backup_header<T: VFile>(file: &mut T, sector_size: u64, primary_alternate_lba: u64) -> anyhow::Result<Header>
+{
+    let backup_lba = if primary_alternate_lba != 0
     {
-        return Err(error::RustructError::Unknown("first usable lba is too low".into()).into());
+        primary_alternate_lba
     }
+    else
+    {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        match file_len.checked_div(sector_size).and_then(|n| n.checked_sub(1))
+        {
+          Some(lba) => lba,
+          None => return Err(error::RustructError::Unknown("file is too short to contain a backup gpt header".into()).into()),
+        }
+    };
+
+    file.seek(SeekFrom::Start(backup_lba * sector_size))?;
+
+    let mut lba = vec![0u8; sector_size as usize];
+    file.read_exact(&mut lba)?;
+
+    let header = parse_header(&mut lba, sector_size)?;
 
-    if !all_zero(&lba1[header_size as usize..]) 
+    if header.my_lba != backup_lba
     {
-        return Err(error::RustructError::Unknown("reserved header tail is not all empty".into()).into());
+        return Err(error::RustructError::Unknown("backup header lba does not match where it was found".into()).into());
     }
 
-    let mut table = vec![0u8; entry_size as usize  * entries as usize];
+    Ok(header)
+}
+
+pub fn gpt_from_file<T: VFile>(file: &mut T, sector_size: u64) -> anyhow::Result<Vec<Partition>>
+{
+    let (header, header_source) = match read_primary_header(file, sector_size)
+    {
+      Ok(header) => (header, "primary"),
+      Err(_) =>
+      {
+        //the primary's alternate_lba couldn't be trusted to parse above, re-read it raw from lba 1
+        file.seek(SeekFrom::Start(sector_size))?;
+        let mut lba1 = vec![0u8; sector_size as usize];
+        let primary_alternate_lba = match file.read_exact(&mut lba1)
+        {
+          Ok(()) => LittleEndian::read_u64(&lba1[0x20..0x28]),
+          Err(_) => 0,
+        };
+        (read_backup_header(file, sector_size, primary_alternate_lba)?, "backup")
+      }
+    };
+
+    file.seek(SeekFrom::Start(header.partition_entry_lba * sector_size))?;
+
+    let mut table = vec![0u8; header.entry_size as usize * header.entries as usize];
     file.read_exact(&mut table)?;
 
+    if header.array_crc != checksum_ieee(&table)
+    {
+        return Err(error::RustructError::Unknown("partition array checksum mismatch".into()).into());
+    }
+
     let mut ret = Vec::with_capacity(16);
-    for id in 0..entries as usize
+    for id in 0..header.entries as usize
     {
-      let entry_size = entry_size as usize;
+      let entry_size = header.entry_size as usize;
       let entry = &table[id * entry_size..(id + 1) * entry_size];
       let type_uuid = &entry[0x00..0x10];
-      if all_zero(type_uuid) 
+      if all_zero(type_uuid)
       {
         continue;
       }
@@ -107,41 +245,163 @@ pub fn gpt_from_file<T: VFile>(file: &mut T, sector_size: u64) -> anyhow::Result
       let first_lba = LittleEndian::read_u64(&entry[0x20..0x28]);
       let last_lba = LittleEndian::read_u64(&entry[0x28..0x30]);
 
-      if first_lba > last_lba || first_lba < first_usable_lba || last_lba > last_usable_lba 
+      if first_lba > last_lba || first_lba < header.first_usable_lba || last_lba > header.last_usable_lba
       {
         return Err(error::RustructError::Unknown("partition entry is out of range".into()).into());
       }
 
-      let attributes = &entry[0x30..0x38];
+      let attributes = LittleEndian::read_u64(&entry[0x30..0x38]);
       let name_data = &entry[0x38..0x80];
       let name_le: Vec<u16> = (0..(0x80 - 0x38) / 2)
             .map(|idx| LittleEndian::read_u16(&name_data[2 * idx..2 * (idx + 1)]))
             .take_while(|val| 0 != *val)
             .collect();
 
-      let name = match String::from_utf16(&name_le) 
+      let name = match String::from_utf16(&name_le)
       {
         Ok(name) => name,
         Err(e) =>  return Err(error::RustructError::Unknown(format!("partition {} has an invalid name: {:?}", id, e)).into()),
       };
 
-      let gpt = GPT{type_uuid : type_uuid.to_vec(), partition_uuid : partition_uuid.to_vec(), 
-                    attributes : attributes.to_vec(), name };
+      let type_uuid = format_guid(type_uuid);
+      let type_name = type_name_for_guid(&type_uuid);
+      let partition_uuid = format_guid(partition_uuid);
+
+      let gpt = GPT{type_uuid, type_name, partition_uuid, name, header_source : header_source.to_string(),
+                    required_partition : 0 != attributes & ATTR_REQUIRED_PARTITION,
+                    no_block_io_protocol : 0 != attributes & ATTR_NO_BLOCK_IO_PROTOCOL,
+                    legacy_bios_bootable : 0 != attributes & ATTR_LEGACY_BIOS_BOOTABLE,
+                    read_only : 0 != attributes & ATTR_READ_ONLY,
+                    hidden : 0 != attributes & ATTR_HIDDEN,
+                    no_automount : 0 != attributes & ATTR_NO_AUTOMOUNT };
 
       ret.push(Partition
       {
         id : id + 1,
         start_sector : first_lba,
         number_of_sector : (last_lba - first_lba + 1),
+        sector_size,
         mbr : None,
         gpt : Some(Arc::new(gpt)),
+        apm : None,
       });
     }
 
     Ok(ret)
 }
 
-fn all_zero(val: &[u8]) -> bool 
+fn all_zero(val: &[u8]) -> bool
 {
   val.iter().all(|x| 0 == *x)
 }
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  const TEST_SECTOR_SIZE: u64 = 512;
+
+  //build a syntactically valid primary-header sector for `entries` x `entry_size` byte partition
+  //entries, with header_crc fixed up so parse_header's checksum check passes
+  fn valid_header_sector(entries: u32, entry_size: u32) -> Vec<u8>
+  {
+    let mut lba = vec![0u8; TEST_SECTOR_SIZE as usize];
+    lba[0x00..0x08].copy_from_slice(b"EFI PART");
+    lba[0x08..0x0c].copy_from_slice(&[0, 0, 1, 0]);
+    LittleEndian::write_u32(&mut lba[0x0c..0x10], 92);
+    LittleEndian::write_u64(&mut lba[0x18..0x20], 1);
+    LittleEndian::write_u64(&mut lba[0x28..0x30], 2 + (u64::from(entry_size) * u64::from(entries)) / TEST_SECTOR_SIZE);
+    LittleEndian::write_u64(&mut lba[0x30..0x38], 1_000_000);
+    LittleEndian::write_u64(&mut lba[0x48..0x50], 2);
+    LittleEndian::write_u32(&mut lba[0x50..0x54], entries);
+    LittleEndian::write_u32(&mut lba[0x54..0x58], entry_size);
+
+    let crc = checksum_ieee(&lba[..92]);
+    LittleEndian::write_u32(&mut lba[0x10..0x14], crc);
+    lba
+  }
+
+  #[test]
+  fn parse_header_accepts_a_well_formed_header()
+  {
+    let mut lba = valid_header_sector(128, 128);
+    let header = parse_header(&mut lba, TEST_SECTOR_SIZE).unwrap();
+    assert_eq!(1, header.my_lba);
+    assert_eq!(128, header.entries);
+    assert_eq!(128, header.entry_size);
+  }
+
+  #[test]
+  fn parse_header_rejects_an_inflated_entry_count()
+  {
+    let mut lba = valid_header_sector(MAX_ENTRIES + 1, 128);
+    let err = parse_header(&mut lba, TEST_SECTOR_SIZE).unwrap_err();
+    assert!(err.to_string().contains("exceeds sane limits"));
+  }
+
+  #[test]
+  fn parse_header_rejects_an_oversized_entry_size()
+  {
+    let mut lba = valid_header_sector(128, MAX_ENTRY_SIZE + 128);
+    let err = parse_header(&mut lba, TEST_SECTOR_SIZE).unwrap_err();
+    assert!(err.to_string().contains("exceeds sane limits"));
+  }
+
+  #[test]
+  fn parse_header_rejects_a_corrupted_checksum()
+  {
+    let mut lba = valid_header_sector(128, 128);
+    lba[0x20] ^= 0xff; //flip a byte covered by the checksum without fixing it up
+    let err = parse_header(&mut lba, TEST_SECTOR_SIZE).unwrap_err();
+    assert!(err.to_string().contains("checksum"));
+  }
+
+  #[test]
+  fn parse_header_rejects_a_header_size_smaller_than_the_minimum()
+  {
+    let mut lba = valid_header_sector(128, 128);
+    LittleEndian::write_u32(&mut lba[0x0c..0x10], 91);
+    let crc = checksum_ieee(&lba[..91]);
+    LittleEndian::write_u32(&mut lba[0x10..0x14], crc);
+    let err = parse_header(&mut lba, TEST_SECTOR_SIZE).unwrap_err();
+    assert!(err.to_string().contains("out of bounds"));
+  }
+
+  #[test]
+  fn parse_header_rejects_a_header_size_larger_than_the_sector()
+  {
+    let mut lba = valid_header_sector(128, 128);
+    //a corrupted header_size claiming to extend past the sector we actually read must not panic
+    LittleEndian::write_u32(&mut lba[0x0c..0x10], TEST_SECTOR_SIZE as u32 + 1);
+    let err = parse_header(&mut lba, TEST_SECTOR_SIZE).unwrap_err();
+    assert!(err.to_string().contains("out of bounds"));
+  }
+
+  #[test]
+  fn format_guid_renders_canonical_mixed_endian_form()
+  {
+    //EFI System Partition GUID, byte-for-byte as it appears on disk (little-endian first three fields)
+    let bytes: [u8; 16] = [
+      0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11,
+      0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+    ];
+    assert_eq!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", format_guid(&bytes));
+  }
+
+  #[test]
+  fn type_name_for_guid_resolves_known_guids_and_defaults_otherwise()
+  {
+    assert_eq!("EFI System Partition", type_name_for_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"));
+    assert_eq!("", type_name_for_guid("00000000-0000-0000-0000-000000000000"));
+  }
+
+  #[test]
+  fn read_backup_header_errors_instead_of_underflowing_on_a_truncated_image()
+  {
+    //primary_alternate_lba unreadable (0) *and* the file is shorter than one sector
+    let mut file = std::io::Cursor::new(vec![0u8; 100]);
+    let err = read_backup_header(&mut file, TEST_SECTOR_SIZE, 0).unwrap_err();
+    assert!(err.to_string().contains("too short"));
+  }
+}
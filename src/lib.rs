@@ -2,10 +2,13 @@
 #![allow(dead_code)]
 mod mbr;
 mod gpt;
+mod apm;
 
 use std::sync::Arc;
 use std::io::BufReader;
 use std::io::SeekFrom;
+use std::io::Read;
+use std::hash::Hasher;
 
 use tap::config_schema;
 use tap::plugin;
@@ -22,6 +25,8 @@ use serde::{Serialize, Deserialize};
 use schemars::{JsonSchema};
 use tap_derive::Reflect;
 
+use sha1::{Sha1, Digest as Sha1Digest};
+
 use mbr::mbr_partition_table;
 
 plugin!("partition", "Volume", "Parse MBR & GPT partition", PartitionPlugin, Arguments);
@@ -29,8 +34,11 @@ plugin!("partition", "Volume", "Parse MBR & GPT partition", PartitionPlugin, Arg
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Arguments
 {
-  #[schemars(with = "TreeNodeIdSchema")] 
+  #[schemars(with = "TreeNodeIdSchema")]
   file : TreeNodeId,
+  //compute per-partition crc32/md5/sha1 digests; off by default as it streams the whole partition
+  #[serde(default)]
+  hash : bool,
 }
 
 #[derive(Debug, Serialize, Deserialize,Default)]
@@ -76,6 +84,17 @@ impl PartitionPlugin
         let partition_builder = partition.to_builder(builder.clone());
         let partition_name = format!("partition_{}", partition.id);
         let partition_node = Node::new(partition_name);
+
+        if args.hash
+        {
+          let digests = match compute_digests(&partition_builder)
+          {
+            Ok(digests) => digests,
+            Err(err) => { parent_node.value().add_attribute(self.name(), None, None); return Err(err) },
+          };
+          partition_node.value().add_attribute("digests", Arc::new(digests), None);
+        }
+
         partition_node.value().add_attribute("data", partition_builder, None);
         partition_node.value().add_attribute("partition", Arc::new(partition), None);
         env.tree.add_child(args.file, partition_node).unwrap();
@@ -93,13 +112,17 @@ impl PartitionPlugin
  */
 const SECTOR_SIZE: usize = 512;
 
+//candidate logical sector sizes to probe for a GPT header, 512 (classic) first then 4096 (4Kn / Advanced Format)
+const GPT_SECTOR_SIZE_CANDIDATES: [u64; 2] = [512, 4096];
+
 #[derive(Debug)]
 pub struct Partitions
 {
-  pub part : Vec<Partition>
+  pub part : Vec<Partition>,
+  pub sector_size : u64,
 }
 
-impl Partitions 
+impl Partitions
 {
   pub fn from_file<T : VFile>(file : &mut T) -> anyhow::Result<Partitions>
   {
@@ -107,22 +130,54 @@ impl Partitions
     file.seek(SeekFrom::Start(0))?;
     let mut disc_header = [0u8; 512];
     file.read_exact(&mut disc_header)?;
-    if 0x55 != disc_header[510] || 0xAA != disc_header[511] 
+    if 0x55 != disc_header[510] || 0xAA != disc_header[511]
     {
-       return Err(error::RustructError::Unknown("Partition header not found".into()).into());
+       return match apm::apm_partition_table(file)
+       {
+         Ok(apm_part) if !apm_part.is_empty() => Ok(Partitions{ part: apm_part, sector_size : SECTOR_SIZE as u64}),
+         _ => Err(error::RustructError::Unknown("Partition header not found".into()).into()),
+       };
     }
-    let mbr_part = mbr_partition_table(&disc_header)?;
+    let mbr_part = mbr_partition_table(file, &disc_header)?;
 
     match mbr_part.len()
     {
       1 if mbr_part[0].is_gpt() => {},
-      _ => return Ok(Partitions{ part: mbr_part}) 
+      0 => return Ok(Partitions{ part: apm_or(file, mbr_part)?, sector_size : SECTOR_SIZE as u64}),
+      _ => return Ok(Partitions{ part: mbr_part, sector_size : SECTOR_SIZE as u64})
     }
- 
-    //must found sector size
-    let gpt_part = gpt::gpt_from_file(file, 512)?;
-    Ok(Partitions{ part: gpt_part})
-  } 
+
+    //sector size isn't known upfront, probe the usual candidates and keep the one whose header validates
+    let (sector_size, gpt_part) = gpt_sector_size_autodetect(file)?;
+    Ok(Partitions{ part: gpt_part, sector_size})
+  }
+}
+
+//no usable MBR entries were found: this may be an Apple Partition Map disk, try that scheme before giving up
+fn apm_or<T : VFile>(file : &mut T, mbr_part : Vec<Partition>) -> anyhow::Result<Vec<Partition>>
+{
+  match apm::apm_partition_table(file)
+  {
+    Ok(apm_part) if !apm_part.is_empty() => Ok(apm_part),
+    _ => Ok(mbr_part),
+  }
+}
+
+//try each candidate logical sector size in turn, keeping the first one whose GPT header signature and crc validate
+fn gpt_sector_size_autodetect<T : VFile>(file : &mut T) -> anyhow::Result<(u64, Vec<Partition>)>
+{
+  let mut first_err = None;
+  for sector_size in GPT_SECTOR_SIZE_CANDIDATES
+  {
+    match gpt::gpt_from_file(file, sector_size)
+    {
+      Ok(part) => return Ok((sector_size, part)),
+      //keep the first candidate's error: it's the one most likely to be informative, since later
+      //candidates are typically rejected for the unrelated reason of being tried at the wrong sector size
+      Err(err) => { first_err.get_or_insert(err); },
+    }
+  }
+  Err(first_err.unwrap())
 }
 
 #[derive(Debug, Reflect)]
@@ -135,13 +190,72 @@ pub struct MBR
 #[derive(Debug, Reflect)]
 pub struct GPT
 {
-  #[reflect(skip)]
-  type_uuid: Vec<u8>,//decode to string ?
-  #[reflect(skip)]
-  partition_uuid: Vec<u8>, //decode to string ?
-  #[reflect(skip)]
-  attributes: Vec<u8>, //decode it ?
+  type_uuid: String,
+  type_name: String,
+  partition_uuid: String,
   name: String,
+  //"primary" or "backup", depending on which header the entry ultimately came from
+  header_source: String,
+  //attributes bitfield, decoded per the GPT spec
+  required_partition: bool,
+  no_block_io_protocol: bool,
+  legacy_bios_bootable: bool,
+  read_only: bool,
+  hidden: bool,
+  no_automount: bool,
+}
+
+#[derive(Debug, Reflect)]
+pub struct APM
+{
+  name: String,
+  partition_type: String,
+}
+
+//read the partition in fixed-size chunks so large volumes don't have to be loaded into memory
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Reflect)]
+pub struct Digests
+{
+  pub crc32 : String,
+  pub md5 : String,
+  pub sha1 : String,
+  //number of bytes actually hashed, so a partial/truncated image is detectable
+  pub hashed_size : u64,
+}
+
+fn compute_digests(builder : &Arc<dyn VFileBuilder>) -> anyhow::Result<Digests>
+{
+  let mut file = builder.open()?;
+
+  let mut crc32 = crc::crc32::Digest::new(crc::crc32::IEEE);
+  let mut md5 = md5::Context::new();
+  let mut sha1 = Sha1::new();
+  let mut hashed_size = 0u64;
+
+  let mut chunk = vec![0u8; HASH_CHUNK_SIZE];
+  loop
+  {
+    let read = file.read(&mut chunk)?;
+    if 0 == read
+    {
+      break;
+    }
+
+    crc32.write(&chunk[..read]);
+    md5.consume(&chunk[..read]);
+    sha1.update(&chunk[..read]);
+    hashed_size += read as u64;
+  }
+
+  Ok(Digests
+  {
+    crc32 : format!("{:08x}", crc32.sum32()),
+    md5 : format!("{:x}", md5.compute()),
+    sha1 : format!("{:x}", sha1.finalize()),
+    hashed_size,
+  })
 }
 
 fn option_to_value<T>(value : &Option<Arc<T>>) -> Option<Value>
@@ -154,12 +268,15 @@ fn option_to_value<T>(value : &Option<Arc<T>>) -> Option<Value>
 pub struct Partition
 {
   pub id : usize,
-  pub start_sector : u64, //set a sector not size ? 
+  pub start_sector : u64, //set a sector not size ?
   pub number_of_sector : u64,  //store a sector not size ?
+  pub sector_size : u64,
   #[reflect(with = "option_to_value")]
-  pub mbr : Option<Arc<MBR>>, 
+  pub mbr : Option<Arc<MBR>>,
   #[reflect(with = "option_to_value")]
   pub gpt : Option<Arc<GPT>>, //We use it this as we don't handle Reflection on enum yet
+  #[reflect(with = "option_to_value")]
+  pub apm : Option<Arc<APM>>,
 }
 
 impl Partition
@@ -190,9 +307,9 @@ impl Partition
   {
     let mut file_ranges = FileRanges::new();
 
-    let start = self.start_sector as u64 * 512;
-    let len = start + self.number_of_sector as u64 * 512;
-    let range = 0 .. len; 
+    let start = self.start_sector as u64 * self.sector_size;
+    let len = self.number_of_sector as u64 * self.sector_size;
+    let range = 0 .. len;
     file_ranges.push(range, start, builder);
     Arc::new(MappedVFileBuilder::new(file_ranges))
   }
@@ -1,54 +1,202 @@
 use std::sync::Arc;
+use std::io::SeekFrom;
+use std::collections::HashSet;
 
+use tap::vfile::VFile;
 use tap::error::{self};
 
 use crate::{Partition, MBR, SECTOR_SIZE};
 
 use byteorder::{ByteOrder, LittleEndian};
 
+//partition types that mark a primary entry as the container for a chain of logical partitions
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+//hard cap on the number of EBRs we'll follow, guards against a corrupt/cyclic chain
+const MAX_EBR_CHAIN: usize = 1024;
+
+struct RawEntry
+{
+  bootable : bool,
+  type_code : u8,
+  start_sector : u32,
+  number_of_sector : u32,
+}
+
+//parse one of the four 16-byte entries of a (M)BR/EBR sector, returning None for an empty entry
+fn parse_entry(sector: &[u8; SECTOR_SIZE], entry_id: usize) -> anyhow::Result<Option<RawEntry>>
+{
+  let first_entry_offset = 446;
+  let entry_size = 16;
+  let entry_offset = first_entry_offset + entry_id * entry_size;
+  let entry = &sector[entry_offset..entry_offset + entry_size];
+  let status = entry[0];
+  let bootable = match status
+  {
+    0x00 => false,
+    0x80 => true,
+    _ =>
+    {
+      return Err(error::RustructError::Unknown(
+                 format!("invalid status code in partition {}: {:x}", entry_id, status)).into())
+    }
+  };
+
+  let type_code = entry[4];
+  if type_code == 0
+  {
+    return Ok(None);
+  }
+
+  let start_sector = LittleEndian::read_u32(&entry[8..]);
+  let number_of_sector = LittleEndian::read_u32(&entry[12..]);
+
+  Ok(Some(RawEntry{bootable, type_code, start_sector, number_of_sector}))
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn sector_with_entry(entry_id: usize, status: u8, type_code: u8, start_sector: u32, number_of_sector: u32) -> [u8; SECTOR_SIZE]
+  {
+    let mut sector = [0u8; SECTOR_SIZE];
+    let offset = 446 + entry_id * 16;
+    sector[offset] = status;
+    sector[offset + 4] = type_code;
+    LittleEndian::write_u32(&mut sector[offset + 8..offset + 12], start_sector);
+    LittleEndian::write_u32(&mut sector[offset + 12..offset + 16], number_of_sector);
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    sector
+  }
+
+  #[test]
+  fn parse_entry_reads_a_bootable_entry()
+  {
+    let sector = sector_with_entry(0, 0x80, 0x07, 2048, 1_000_000);
+    let entry = parse_entry(&sector, 0).unwrap().unwrap();
+    assert!(entry.bootable);
+    assert_eq!(0x07, entry.type_code);
+    assert_eq!(2048, entry.start_sector);
+    assert_eq!(1_000_000, entry.number_of_sector);
+  }
+
+  #[test]
+  fn parse_entry_treats_a_zero_type_code_as_empty()
+  {
+    let sector = sector_with_entry(1, 0x00, 0x00, 0, 0);
+    assert!(parse_entry(&sector, 1).unwrap().is_none());
+  }
+
+  #[test]
+  fn parse_entry_rejects_an_invalid_status_byte()
+  {
+    let sector = sector_with_entry(2, 0x42, 0x83, 0, 0);
+    assert!(parse_entry(&sector, 2).is_err());
+  }
+
+  #[test]
+  fn read_ebr_chain_detects_a_cycle()
+  {
+    //an EBR whose "next" entry points back to the extended partition's own start sector
+    let mut ebr = sector_with_entry(0, 0x00, 0x83, 0, 100);
+    let next_offset = 446 + 16;
+    ebr[next_offset + 4] = 0x05;
+    LittleEndian::write_u32(&mut ebr[next_offset + 8..next_offset + 12], 0);
+    let mut file = std::io::Cursor::new(ebr.to_vec());
+    let err = read_ebr_chain(&mut file, 0).unwrap_err();
+    assert!(err.to_string().contains("cycle"));
+  }
+}
+
 /// Read a DOS/MBR partition table from a 512-byte boot sector, providing a disc sector size.
-pub fn mbr_partition_table(sector: &[u8; SECTOR_SIZE]) -> anyhow::Result<Vec<Partition>> 
+pub fn mbr_partition_table<T: VFile>(file: &mut T, sector: &[u8; SECTOR_SIZE]) -> anyhow::Result<Vec<Partition>>
 {
   let mut partitions = Vec::with_capacity(4);
+  let mut extended_start_sector = None;
 
-  for entry_id in 0..4 
+  for entry_id in 0..4
   {
-    let first_entry_offset = 446;
-    let entry_size = 16;
-    let entry_offset = first_entry_offset + entry_id * entry_size;
-    let partition = &sector[entry_offset..entry_offset + entry_size];
-    let status = partition[0];
-    let bootable = match status 
+    let entry = match parse_entry(sector, entry_id)?
     {
-      0x00 => false,
-      0x80 => true,
-      _ => 
-      {
-        return Err(error::RustructError::Unknown(
-                   format!("invalid status code in partition {}: {:x}", entry_id, status)).into())
-      }
+      Some(entry) => entry,
+      None => continue,
     };
 
-    let type_code = partition[4];
-    if type_code == 0 
+    if EXTENDED_TYPES.contains(&entry.type_code)
     {
-      continue;
+      extended_start_sector = Some(entry.start_sector as u64);
     }
 
-    let start_sector = LittleEndian::read_u32(&partition[8..]);
-    let number_of_sector = LittleEndian::read_u32(&partition[12..]); 
-
-    let mbr = MBR{bootable, type_code};
-
-    partitions.push(Partition 
+    partitions.push(Partition
     {
       id: entry_id + 1,
-      start_sector : start_sector as u64,
-      number_of_sector : number_of_sector as u64,
-      mbr : Some(Arc::new(mbr)),
-      gpt : None
+      start_sector : entry.start_sector as u64,
+      number_of_sector : entry.number_of_sector as u64,
+      sector_size : SECTOR_SIZE as u64,
+      mbr : Some(Arc::new(MBR{bootable: entry.bootable, type_code: entry.type_code})),
+      gpt : None,
+      apm : None,
     });
   }
 
+  if let Some(extended_start_sector) = extended_start_sector
+  {
+    partitions.extend(read_ebr_chain(file, extended_start_sector)?);
+  }
+
   Ok(partitions)
 }
+
+//walk the linked list of Extended Boot Records inside an extended partition, collecting each
+//logical partition it describes. The first entry of an EBR is the logical partition itself,
+//relative to that EBR's own lba; the second entry, if present, points to the next EBR,
+//relative to the start of the extended partition.
+fn read_ebr_chain<T: VFile>(file: &mut T, extended_start_sector: u64) -> anyhow::Result<Vec<Partition>>
+{
+  let mut logical = Vec::new();
+  let mut visited = HashSet::new();
+  let mut ebr_lba = extended_start_sector;
+  let mut next_id = 5;
+
+  for _ in 0..MAX_EBR_CHAIN
+  {
+    if !visited.insert(ebr_lba)
+    {
+      return Err(error::RustructError::Unknown("cycle detected while walking the EBR chain".into()).into());
+    }
+
+    file.seek(SeekFrom::Start(ebr_lba * SECTOR_SIZE as u64))?;
+    let mut ebr = [0u8; SECTOR_SIZE];
+    file.read_exact(&mut ebr)?;
+
+    if 0x55 != ebr[510] || 0xAA != ebr[511]
+    {
+      return Err(error::RustructError::Unknown("invalid EBR signature".into()).into());
+    }
+
+    if let Some(entry) = parse_entry(&ebr, 0)?
+    {
+      logical.push(Partition
+      {
+        id: next_id,
+        start_sector : ebr_lba + entry.start_sector as u64,
+        number_of_sector : entry.number_of_sector as u64,
+        sector_size : SECTOR_SIZE as u64,
+        mbr : Some(Arc::new(MBR{bootable: entry.bootable, type_code: entry.type_code})),
+        gpt : None,
+        apm : None,
+      });
+      next_id += 1;
+    }
+
+    match parse_entry(&ebr, 1)?
+    {
+      Some(next) => ebr_lba = extended_start_sector + next.start_sector as u64,
+      _ => break,
+    }
+  }
+
+  Ok(logical)
+}